@@ -10,22 +10,10 @@ extern crate serde;
 #[macro_use] extern crate serde_derive;
 
 
-use crate::service::{MCServerLogOptions, MCServerCommands, MCServerOptionsBuilder, MCContainerService};
-
 use crate::server::Server;
 
 const DEFAULT_MC_PORT: u32 = 25565;
 
-#[derive(Debug)]
-pub struct MCWorker {
-    id: u32,
-    name: String,
-    container: String,
-    volume: String,
-    status: String,
-    port: u32
-}
-
 pub mod repository;
 
 mod service;
@@ -34,7 +22,8 @@ mod server;
 
 fn main() {
     env_logger::init();
-    let mut server = Server::new();
+    let mut server = Server::new()
+        .unwrap_or_else(|e| panic!("failed to connect to docker: {}", e.to_string()));
     info!("Starting server");
     server.run();
 }
\ No newline at end of file
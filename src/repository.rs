@@ -1,14 +1,92 @@
-use rusqlite::Connection;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
 
+#[derive(Debug, Clone, Serialize)]
+pub struct MCWorker {
+    pub id: i64,
+    pub name: String,
+    pub container: String,
+    pub volume: String,
+    pub status: String,
+    pub port: u32,
+}
+
+/// Cheaply `Clone`-able handle onto a single shared connection, so the HTTP
+/// service layer and the background event watcher aren't each opening their
+/// own connection to `default.db` and racing each other on writes.
+#[derive(Clone)]
 pub struct MCRepository {
-    conn: Connection,
+    conn: Arc<Mutex<Connection>>,
 }
 
 impl MCRepository {
     pub fn new() -> Self {
         let conn = Connection::open("default.db").expect("Couldn't open db connection");
-        MCRepository {
-            conn
-        }
+        let repo = MCRepository { conn: Arc::new(Mutex::new(conn)) };
+        repo.migrate();
+        repo
+    }
+
+    fn migrate(&self) {
+        self.conn.lock().unwrap().execute(
+            "CREATE TABLE IF NOT EXISTS workers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                container TEXT NOT NULL UNIQUE,
+                volume TEXT NOT NULL,
+                status TEXT NOT NULL,
+                port INTEGER NOT NULL
+            )",
+            params![],
+        ).expect("failed to migrate worker schema");
+    }
+
+    pub fn insert(&self, worker: &MCWorker) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO workers (name, container, volume, status, port) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![worker.name, worker.container, worker.volume, worker.status, worker.port],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_status(&self, container: &str, status: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE workers SET status = ?1 WHERE container = ?2",
+            params![status, container],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_by_container(&self, container: &str) -> rusqlite::Result<Option<MCWorker>> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT id, name, container, volume, status, port FROM workers WHERE container = ?1",
+            params![container],
+            |row| Self::worker_from_row(row),
+        ).optional()
+    }
+
+    pub fn list(&self) -> rusqlite::Result<Vec<MCWorker>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, container, volume, status, port FROM workers"
+        )?;
+        let rows = stmt.query_map(params![], |row| Self::worker_from_row(row))?;
+        rows.collect()
+    }
+
+    pub fn delete(&self, container: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute("DELETE FROM workers WHERE container = ?1", params![container])?;
+        Ok(())
+    }
+
+    fn worker_from_row(row: &rusqlite::Row) -> rusqlite::Result<MCWorker> {
+        Ok(MCWorker {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            container: row.get(2)?,
+            volume: row.get(3)?,
+            status: row.get(4)?,
+            port: row.get(5)?,
+        })
     }
 }
@@ -1,9 +1,20 @@
-use crate::service::{MCService, MCContainerService, MCServerOptionsBuilder};
-use rouille::Response;
+use crate::repository::MCRepository;
+use crate::service::{connect_docker, watch_worker_events, MCContainer, MCContainerService, MCService, MCServerCommands, MCServerLogOptions, MCServerOptionsBuilder};
+use rouille::{Response, ResponseBody};
+use shiplift::Docker;
+use std::io::Read;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use futures::stream::StreamExt;
+use tokio::runtime::Runtime;
 
-pub struct Server;
+pub struct Server {
+    docker: Docker,
+    runtime: Arc<Runtime>,
+    mc_service: Arc<Mutex<MCService>>,
+    container_service: Arc<Mutex<MCContainerService>>,
+}
 
 #[derive(Serialize)]
 struct BasicResponse {
@@ -13,6 +24,19 @@ struct BasicResponse {
     error: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct CreateContainerRequest {
+    name: String,
+    volume: String,
+    port: Option<u32>,
+    memory: Option<u64>,
+    memory_swap: Option<i64>,
+    cpu_shares: Option<u32>,
+    restart_policy: Option<String>,
+    restart_policy_max_retry_count: Option<u64>,
+    image_tag: Option<String>,
+}
+
 impl BasicResponse {
     pub fn success(response: String) -> Self {
         BasicResponse {
@@ -28,24 +52,103 @@ impl BasicResponse {
     }
 }
 
+/// Bridges a `Stream<Item = Result<String, Error>>` of log lines to a
+/// blocking `Read`, driving the stream on its own runtime so a long-lived
+/// follow connection doesn't hold up the server's shared request runtime.
+struct LogStreamReader {
+    runtime: Runtime,
+    stream: std::pin::Pin<Box<dyn futures::stream::Stream<Item = Result<String, shiplift::Error>> + Send>>,
+    leftover: Vec<u8>,
+}
+
+impl LogStreamReader {
+    fn new(docker: Docker, container: MCContainer, options: MCServerLogOptions) -> Self {
+        LogStreamReader {
+            runtime: Runtime::new().expect("failed to make tokio runtime"),
+            stream: Box::pin(MCContainerService::logs_follow(docker, container, options)),
+            leftover: Vec::new(),
+        }
+    }
+}
+
+impl Read for LogStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.leftover.is_empty() {
+            match self.runtime.block_on(self.stream.next()) {
+                Some(Ok(line)) => self.leftover = format!("data: {}\n\n", line).into_bytes(),
+                Some(Err(e)) => {
+                    error!("Error streaming logs: {}", e);
+                    return Ok(0);
+                },
+                None => return Ok(0),
+            }
+        }
+        let written = std::cmp::min(buf.len(), self.leftover.len());
+        buf[..written].copy_from_slice(&self.leftover[..written]);
+        self.leftover.drain(..written);
+        Ok(written)
+    }
+}
+
 impl Server {
-    pub fn new() -> Self {
-        Server
+    pub fn new() -> Result<Self, crate::service::MCError> {
+        let docker = connect_docker()?;
+        let repo = MCRepository::new();
+        let runtime = Runtime::new().expect("failed to make tokio runtime");
+        runtime.spawn(watch_worker_events(docker.clone(), repo.clone()));
+        Ok(Server {
+            runtime: Arc::new(runtime),
+            mc_service: Arc::new(Mutex::new(MCService::new(docker.clone(), repo.clone()))),
+            container_service: Arc::new(Mutex::new(MCContainerService::new(docker.clone(), repo))),
+            docker,
+        })
     }
 
     pub fn run(&mut self) {
 
         info!("Listening on port 8081");
+        let docker = self.docker.clone();
+        let runtime = self.runtime.clone();
+        let mc_service = self.mc_service.clone();
+        let container_service = self.container_service.clone();
         rouille::start_server("localhost:8081", move |request| {
             info!("Processing incoming request");
-            let mut mc_service = MCService::new();
-            let mut container_service = MCContainerService::new();
 
             router!(request,
+                (GET) (/container) => {
+                    let mc_service = mc_service.lock().unwrap();
+                    match mc_service.list_workers() {
+                        Ok(workers) => {Response::json(&workers)},
+                        Err(e) => {
+                            Response::json(&BasicResponse::error(e.to_string()))
+                                .with_status_code(400)
+                        },
+                    }
+                },
                 (POST) (/container) => {
-                    let response = mc_service.create(&MCServerOptionsBuilder::new("SomeName".to_string(),
-                                                                                  PathBuf::from_str("/Users/kristoncosta/workspace/tmp-mc")
-                                                                                      .unwrap()).build());
+                    let body: CreateContainerRequest = try_or_400!(rouille::input::json_input(request));
+                    let mut builder = MCServerOptionsBuilder::new(body.name,
+                                                                   PathBuf::from_str(&body.volume).unwrap());
+                    if let Some(port) = body.port {
+                        builder = builder.port(port);
+                    }
+                    if let Some(memory) = body.memory {
+                        builder = builder.memory(memory);
+                    }
+                    if let Some(memory_swap) = body.memory_swap {
+                        builder = builder.memory_swap(memory_swap);
+                    }
+                    if let Some(cpu_shares) = body.cpu_shares {
+                        builder = builder.cpu_shares(cpu_shares);
+                    }
+                    if let Some(restart_policy) = body.restart_policy {
+                        builder = builder.restart_policy(restart_policy, body.restart_policy_max_retry_count.unwrap_or(0));
+                    }
+                    if let Some(image_tag) = body.image_tag {
+                        builder = builder.image_tag(image_tag);
+                    }
+                    let mut mc_service = mc_service.lock().unwrap();
+                    let response = runtime.block_on(mc_service.create(&builder.build()));
                     match response {
                         Ok(x) => {Response::json(&BasicResponse::success(x.id))},
                         Err(e) => {
@@ -56,7 +159,8 @@ impl Server {
                 },
                 (POST) (/container/{id: String}/start) => {
                     let container = MCService::get_container(id);
-                    let response = container_service.start(&container);
+                    let mut container_service = container_service.lock().unwrap();
+                    let response = runtime.block_on(container_service.start(&container));
                     match response {
                         Ok(_) => {Response::json(&BasicResponse::success("success".to_string()))},
                         Err(e) => {
@@ -67,7 +171,8 @@ impl Server {
                 },
                 (POST) (/container/{id: String}/stop) => {
                     let container = MCService::get_container(id);
-                    let response = container_service.stop(&container);
+                    let mut container_service = container_service.lock().unwrap();
+                    let response = runtime.block_on(container_service.stop(&container));
                     match response {
                         Ok(_) => {Response::json(&BasicResponse::success("success".to_string()))},
                         Err(e) => {
@@ -76,9 +181,58 @@ impl Server {
                             },
                     }
                 },
+                (GET) (/container/{id: String}/status) => {
+                    let container = MCService::get_container(id);
+                    let mut container_service = container_service.lock().unwrap();
+                    let response = runtime.block_on(container_service.status(&container));
+                    match response {
+                        Ok(status) => {Response::json(&status)},
+                        Err(e) => {
+                            Response::json(&BasicResponse::error(e.to_string()))
+                                .with_status_code(400)
+                        },
+                    }
+                },
+                (GET) (/container/{id: String}/logs) => {
+                    let follow = request.get_param("follow").map_or(false, |v| v == "true");
+                    let container = MCService::get_container(id);
+                    if follow {
+                        let reader = LogStreamReader::new(docker.clone(), container, MCServerLogOptions::default());
+                        Response {
+                            status_code: 200,
+                            headers: vec![("Content-Type".into(), "text/event-stream".into())],
+                            data: ResponseBody::from_reader(reader),
+                            upgrade: None,
+                        }
+                    } else {
+                        let mut container_service = container_service.lock().unwrap();
+                        let response = runtime.block_on(container_service.logs(&container, &MCServerLogOptions::default()));
+                        match response {
+                            Ok(lines) => {Response::json(&lines)},
+                            Err(_) => {
+                                Response::json(&BasicResponse::error("failed to fetch logs".to_string()))
+                                    .with_status_code(400)
+                            },
+                        }
+                    }
+                },
+                (POST) (/container/{id: String}/command) => {
+                    let command: MCServerCommands = try_or_400!(rouille::input::json_input(request));
+                    let container = MCService::get_container(id);
+                    let mut container_service = container_service.lock().unwrap();
+                    let response = runtime.block_on(container_service.run_command(&container, command));
+                    match response {
+                        Ok(output) => {Response::json(&output)},
+                        Err(e) => {
+                            Response::json(&BasicResponse::error(e.to_string()))
+                                .with_status_code(400)
+                            },
+                    }
+                },
                 (DELETE) (/container/{id: String}) => {
                     let container = MCService::get_container(id);
-                    let response = container_service.rm(&container);
+                    let mut container_service = container_service.lock().unwrap();
+                    let response = runtime.block_on(container_service.rm(&container));
                     match response {
                         Ok(_) => {Response::json(&BasicResponse::success("success".to_string()))},
                         Err(e) => {
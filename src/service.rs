@@ -1,15 +1,15 @@
-use crate::repository::MCRepository;
+use crate::repository::{MCRepository, MCWorker};
 
 use shiplift::{Docker, Container, LogsOptions, Error, RmContainerOptions};
-use shiplift::builder::{ContainerOptions, RmContainerOptionsBuilder};
-use shiplift::rep::{ContainerCreateInfo, ContainerDetails};
+use shiplift::builder::{ContainerOptions, EventsOptions, ExecContainerOptions, ImageListOptions, PullOptions};
+use shiplift::rep::{ContainerCreateInfo, ContainerDetails, ExecDetails};
 
-use tokio::prelude::{Future, Stream};
-use tokio::runtime::Runtime;
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use async_stream::stream;
 
 use log::{info, warn};
 use crate::{DEFAULT_MC_PORT};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 pub enum MCError {
     FailedToCreateContainer,
@@ -19,6 +19,9 @@ pub enum MCError {
     FailedToRunCommand(String, MCServerCommands),
     FailedToRMContainer(String),
     ContainerError(String, String),
+    FailedToConnectToDocker(String),
+    RepositoryError(String),
+    FailedToPullImage(String),
 }
 
 impl ToString for MCError {
@@ -31,51 +34,107 @@ impl ToString for MCError {
             MCError::FailedToRunCommand(x, c) => {format!("failed to run command {:?} on container {}", c, x)},
             MCError::FailedToRMContainer(x) => {format!("failed to rm container {}", x)},
             MCError::ContainerError(_, e) => {format!("{}", e)},
+            MCError::FailedToConnectToDocker(x) => {format!("failed to connect to docker host {}", x)},
+            MCError::RepositoryError(e) => {format!("repository error: {}", e)},
+            MCError::FailedToPullImage(x) => {format!("failed to pull image {}", x)},
         }
     }
 }
 
+const DEFAULT_DOCKER_SOCKET: &str = "unix:///var/run/docker.sock";
+
+/// Resolves the Docker connection from `DOCKER_HOST`, falling back to the
+/// local unix socket, so every service shares one connection configuration.
+pub(crate) fn connect_docker() -> Result<Docker, MCError> {
+    let host = std::env::var("DOCKER_HOST").unwrap_or_else(|_| DEFAULT_DOCKER_SOCKET.to_string());
+    let uri = host.parse().map_err(|_| MCError::FailedToConnectToDocker(host.clone()))?;
+    Ok(Docker::host(uri))
+}
+
+#[derive(Debug, Serialize)]
+pub struct MCCommandOutput {
+    pub output: String,
+    pub exit_code: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MCStatus {
+    pub state: String,
+    pub exit_code: i64,
+    pub started_at: String,
+    pub health: Option<String>,
+    pub port: Option<u32>,
+}
+
 pub struct MCService {
     repo: MCRepository,
     image: String,
     docker: Docker,
-    runtime: Runtime,
 }
 
 impl MCService {
-    pub fn new() -> Self {
+    pub fn new(docker: Docker, repo: MCRepository) -> Self {
         MCService {
-            docker: Docker::host("http://localhost:2375".parse().unwrap()),
-            repo: MCRepository::new(),
+            docker,
+            repo,
             image: "itzg/minecraft-server".to_string(),
-            runtime: Runtime::new().expect("failed to make tokio runtime"),
         }
     }
 
-    pub fn create(&mut self, options: &MCServerOptions) -> Result<MCContainer, MCError> {
+    pub async fn create(&mut self, options: &MCServerOptions) -> Result<MCContainer, MCError> {
+        let tag = options.image_tag.as_deref().unwrap_or("latest");
+        let image = format!("{}:{}", self.image, tag);
+        self.ensure_image(&image, tag).await?;
+
         let volume_path = options.volume.as_path().to_str().expect("unable to load path");
-        let options = ContainerOptions::builder(self.image.as_ref())
-            .env(vec!["EULA=TRUE"])
+        let mut builder = ContainerOptions::builder(&image);
+        builder.env(vec!["EULA=TRUE"])
             .volumes(vec![&format!("{}:/data", volume_path)])
             .attach_stdin(true)
-            .expose(options.port, "tcp", DEFAULT_MC_PORT)
-            .build();
+            .expose(options.port, "tcp", DEFAULT_MC_PORT);
 
-        let runner = self.docker
-            .containers()
-            .create(&options)
-            .map(move |info| return info)
-            .map_err(|e| eprintln!("Error: {}", e));
+        if let Some(memory) = options.memory {
+            builder.memory(memory);
+        }
+        if let Some(memory_swap) = options.memory_swap {
+            builder.memory_swap(memory_swap);
+        }
+        if let Some(cpu_shares) = options.cpu_shares {
+            builder.cpu_shares(cpu_shares);
+        }
+        if let Some(ref restart_policy) = options.restart_policy {
+            builder.restart_policy(&restart_policy.name, restart_policy.maximum_retry_count);
+        }
 
-        let info: ContainerCreateInfo = self.runtime.block_on(runner).map_err(|e| {
-            MCError::FailedToCreateContainer
-        })?;
+        let container_options = builder.build();
+
+        let info: ContainerCreateInfo = self.docker
+            .containers()
+            .create(&container_options)
+            .await
+            .map_err(|e| {
+                error!("Error: {}", e);
+                MCError::FailedToCreateContainer
+            })?;
 
         if let Some(warnings) = info.warnings {
             for warning in warnings {
                 warn!("Warning [Container {}]: {}", info.id, warning);
             }
         }
+
+        let worker = MCWorker {
+            id: 0,
+            name: options.name.clone(),
+            container: info.id.clone(),
+            volume: volume_path.to_string(),
+            status: "created".to_string(),
+            port: options.port,
+        };
+        if let Err(e) = self.repo.insert(&worker) {
+            error!("Failed to persist worker {}: {}", info.id, e);
+        }
+
         Ok(Self::get_container(info.id))
     }
 
@@ -85,7 +144,49 @@ impl MCService {
         }
     }
 
+    pub fn list_workers(&self) -> Result<Vec<MCWorker>, MCError> {
+        self.repo.list().map_err(|e| MCError::RepositoryError(e.to_string()))
+    }
 
+    /// Pulls `image` if it isn't already present locally, logging layer
+    /// download progress as it goes, so `create` doesn't fail deep inside
+    /// container creation on a clean host.
+    async fn ensure_image(&self, image: &str, tag: &str) -> Result<(), MCError> {
+        // `filter_name` matches by repository, not by `repo:tag` reference,
+        // so filter on the untagged repo and check the returned tags
+        // ourselves for the one we actually need.
+        let existing = self.docker.images()
+            .list(&ImageListOptions::builder().filter_name(&self.image).build())
+            .await
+            .map_err(|e| {
+                error!("Error: {}", e);
+                MCError::FailedToPullImage(image.to_string())
+            })?;
+        let already_present = existing.iter().any(|img| {
+            img.repo_tags.as_ref()
+                .map_or(false, |tags| tags.iter().any(|t| t == image))
+        });
+        if already_present {
+            return Ok(());
+        }
+
+        info!("Pulling image {}", image);
+        let mut pull_stream = self.docker.images().pull(&PullOptions::builder()
+            .image(&self.image)
+            .tag(tag)
+            .build());
+
+        while let Some(status) = pull_stream.next().await {
+            match status {
+                Ok(status) => info!("Pulling {}: {:?}", image, status),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    return Err(MCError::FailedToPullImage(image.to_string()));
+                },
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct MCContainer {
@@ -95,48 +196,85 @@ pub struct MCContainer {
 pub struct MCContainerService {
     repo: MCRepository,
     docker: Docker,
-    runtime: Runtime,
 }
 
 impl MCContainerService {
-    pub fn new() -> Self {
+    pub fn new(docker: Docker, repo: MCRepository) -> Self {
         MCContainerService {
-            docker: Docker::host("http://localhost:2375".parse().unwrap()),
-            repo: MCRepository::new(),
-            runtime: Runtime::new().expect("failed to make tokio runtime"),
+            docker,
+            repo,
         }
     }
 
-    pub fn status(&mut self, container: &MCContainer) -> Result<(), ()> {
-        Ok(())
+    pub async fn status(&mut self, container: &MCContainer) -> Result<MCStatus, MCError> {
+        info!("Checking status of container: {}", container.id);
+        let ref container_id = container.id;
+        let container = Container::new(&self.docker, container.id.clone());
+        let details: ContainerDetails = container.inspect().await.map_err(|_| {
+            MCError::FailedToInspectContainer(container_id.clone())
+        })?;
+
+        // Docker's own `Status` string (created/running/paused/exited/dead)
+        // already distinguishes a normal exit from the `dead` flag, which
+        // means an unrecoverable/failed-removal state, not a plain exit —
+        // deriving `"exited"` from `dead` mislabels every normal exit as
+        // `"stopped"`.
+        let state = details.state.status.clone();
+
+        // The container-side port is whatever the worker was created with
+        // (`create` exposes `options.port`, not the `DEFAULT_MC_PORT`
+        // constant), so look the binding up by the persisted port and fall
+        // back to the first host binding found if the worker row is missing
+        // or has no binding under that port.
+        let persisted_port = self.repo.get_by_container(container_id).ok().flatten().map(|worker| worker.port);
+        let port = persisted_port
+            .and_then(|container_port| {
+                details.network_settings.ports.as_ref()
+                    .and_then(|ports| ports.get(&format!("{}/tcp", container_port)))
+                    .and_then(|bindings| bindings.as_ref())
+                    .and_then(|bindings| bindings.first())
+                    .and_then(|binding| binding.host_port.parse::<u32>().ok())
+            })
+            .or_else(|| {
+                details.network_settings.ports.as_ref()
+                    .into_iter()
+                    .flat_map(|ports| ports.values())
+                    .filter_map(|bindings| bindings.as_ref())
+                    .filter_map(|bindings| bindings.first())
+                    .filter_map(|binding| binding.host_port.parse::<u32>().ok())
+                    .next()
+            });
+
+        Ok(MCStatus {
+            state,
+            exit_code: details.state.exit_code,
+            started_at: details.state.started_at,
+            health: details.state.health.map(|health| health.status),
+            port,
+        })
     }
 
-    pub fn start(&mut self, container: &MCContainer) -> Result<(), MCError> {
+    pub async fn start(&mut self, container: &MCContainer) -> Result<(), MCError> {
         info!("Starting container: {}", container.id);
         let ref container_id = container.id;
         let container = Container::new(&self.docker, container_id.clone());
-        self.runtime.block_on(
-            container.start()
-                .map_err(|e| {
-                    match e {
-                        Error::Fault {
-                            code,
-                            ..
-                        } => {
-                            if code.is_client_error() || code.is_server_error() {
-                                error!("{}", e);
-                            }
-                        },
-                        _ => {error!("{}", e)},
-                    }
-                }))
-            .map_err(|e|
-                MCError::FailedToStartContainer(container_id.clone())
-            )?;
-        let container_info: ContainerDetails = self.runtime
-            .block_on(
-                container.inspect())
+        container.start().await
             .map_err(|e| {
+                match e {
+                    Error::Fault {
+                        code,
+                        ..
+                    } => {
+                        if code.is_client_error() || code.is_server_error() {
+                            error!("{}", e);
+                        }
+                    },
+                    _ => {error!("{}", e)},
+                }
+                MCError::FailedToStartContainer(container_id.clone())
+            })?;
+        let container_info: ContainerDetails = container.inspect().await
+            .map_err(|_| {
                 MCError::FailedToInspectContainer(container_id.clone())
             })?;
         if !container_info.state.error.is_empty() {
@@ -146,39 +284,52 @@ impl MCContainerService {
         Ok(())
     }
 
-    pub fn stop(&mut self, container: &MCContainer) -> Result<(), MCError> {
+    pub async fn stop(&mut self, container: &MCContainer) -> Result<(), MCError> {
         info!("Stopping container: {}", container.id);
         let ref container_id = container.id;
         let container = Container::new(&self.docker, container.id.clone());
-        self.runtime.block_on(
-            container
-                .stop(None)
-        ).map_err(|_| {
+        container.stop(None).await.map_err(|_| {
             MCError::FailedToStopContainer(container_id.clone())
         })?;
         Ok(())
     }
 
-    pub fn run_command(&mut self, container: &MCContainer, command: MCServerCommands) -> Result<(), MCError> {
+    pub async fn run_command(&mut self, container: &MCContainer, command: MCServerCommands) -> Result<MCCommandOutput, MCError> {
         info!("Running command {:?} on container {}", command, container.id);
         let ref container_id = container.id;
         let command_for_error = command.clone();
         let container = Container::new(&self.docker, container.id.clone());
-        use std::io::prelude::*;
-        self.runtime.block_on(
-            container
-            .attach()
-            .map(move |mut mul| {
-                mul.write_all(command.to_string().as_bytes());
-                mul.flush();
-            })
-        ).map_err(|_| {
-            MCError::FailedToRunCommand(container_id.clone(), command_for_error)
+
+        let exec_options = ExecContainerOptions::builder()
+            .cmd(vec!["rcon-cli", &command.to_rcon_command()])
+            .attach_stdout(true)
+            .attach_stderr(true)
+            .build();
+
+        let exec = container.exec_create(&exec_options).await.map_err(|_| {
+            MCError::FailedToRunCommand(container_id.clone(), command_for_error.clone())
         })?;
-        Ok(())
+
+        let chunks = exec.start()
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(|e| {
+                error!("Error: {:?}", e);
+                MCError::FailedToRunCommand(container_id.clone(), command_for_error.clone())
+            })?;
+        let output = chunks.into_iter().map(|chunk| chunk.as_string_lossy()).collect::<Vec<_>>().join("");
+
+        let details: ExecDetails = exec.inspect().await.map_err(|_| {
+            MCError::FailedToRunCommand(container_id.clone(), command_for_error.clone())
+        })?;
+
+        Ok(MCCommandOutput {
+            output,
+            exit_code: details.exit_code,
+        })
     }
 
-    pub fn logs(&mut self, container: &MCContainer, options: &MCServerLogOptions) -> Result<Vec<String>, ()> {
+    pub async fn logs(&mut self, container: &MCContainer, options: &MCServerLogOptions) -> Result<Vec<String>, ()> {
         info!("Logging container: {}", container.id);
         let container = Container::new(&self.docker, container.id.clone());
         let log_runner = container.logs(&LogsOptions::builder()
@@ -186,45 +337,135 @@ impl MCContainerService {
             .stdout(true)
             .tail(&options.limit)
             .build());
-        let logs: Vec<_> = self.runtime.block_on(
-            log_runner.collect()
-            .map(|res| return res)
-            .map_err(|e| error!("Error: {:?}", e))
-        ).expect("");
+        let logs = log_runner
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(|e| error!("Error: {:?}", e))?;
         Ok(logs.into_iter().map(|l| l.as_string_lossy()).collect())
     }
 
-    pub fn rm(&mut self, container: &MCContainer) -> Result<(), MCError> {
+    /// Tails a container's logs as they are produced. Each yielded item is a
+    /// complete UTF-8 line: raw TTY chunks are buffered and split on `\n`
+    /// since a chunk may hold zero, one, or several line fragments and a
+    /// line may span more than one chunk.
+    pub fn logs_follow(docker: Docker, container: MCContainer, options: MCServerLogOptions) -> impl Stream<Item = Result<String, Error>> {
+        stream! {
+            let raw_container = Container::new(&docker, container.id.clone());
+            let log_stream = raw_container.logs(&LogsOptions::builder()
+                .stderr(true)
+                .stdout(true)
+                .tail(&options.limit)
+                .follow(true)
+                .build());
+            futures::pin_mut!(log_stream);
+
+            let mut buffer: Vec<u8> = Vec::new();
+            while let Some(chunk) = log_stream.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        // Buffer the raw bytes, not a per-chunk lossy decode, so a
+                        // multi-byte character split across two TTY chunks is
+                        // reassembled before we ever touch UTF-8.
+                        buffer.extend_from_slice(&chunk);
+                        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                            let line: Vec<u8> = buffer.drain(..=pos).collect();
+                            yield Ok(String::from_utf8_lossy(&line[..line.len() - 1]).into_owned());
+                        }
+                    },
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    },
+                }
+            }
+            if !buffer.is_empty() {
+                yield Ok(String::from_utf8_lossy(&buffer).into_owned());
+            }
+        }
+    }
+
+    pub async fn rm(&mut self, container: &MCContainer) -> Result<(), MCError> {
         info!("Removing container: {}", container.id);
         let ref container_id = container.id;
         let container = Container::new(&self.docker, container.id.clone());
         let options = RmContainerOptions::builder()
             .force(true)
             .build();
-        self.runtime.block_on(
-            container
-                .remove(options)
-
-        ).map_err(|e| {
+        container.remove(options).await.map_err(|_| {
             MCError::FailedToRMContainer(container_id.clone())
-        })
-        // Ok(())
+        })?;
+
+        if let Err(e) = self.repo.delete(container_id) {
+            error!("Failed to delete persisted worker {}: {}", container_id, e);
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+const MANAGED_WORKER_EVENT_ACTIONS: [&str; 4] = ["start", "die", "stop", "destroy"];
+
+/// Mirrors container lifecycle transitions from the Docker events stream
+/// into the worker repository so persisted status reflects out-of-band
+/// changes (a crash, a `docker stop` from the CLI, a host reboot) and not
+/// just the requests this process happened to handle.
+pub async fn watch_worker_events(docker: Docker, repo: MCRepository) {
+    let mut events = docker.events(&EventsOptions::builder().build());
+
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(event) => {
+                if event.typ != "container" || !MANAGED_WORKER_EVENT_ACTIONS.contains(&event.action.as_str()) {
+                    continue;
+                }
+                let result = if event.action == "destroy" {
+                    repo.delete(&event.actor.id)
+                } else {
+                    let state = match event.action.as_str() {
+                        "start" => "running",
+                        "die" => "exited",
+                        "stop" => "stopped",
+                        other => other,
+                    };
+                    repo.update_status(&event.actor.id, state)
+                };
+                if let Err(e) = result {
+                    error!("Failed to persist status for container {}: {}", event.actor.id, e);
+                }
+            },
+            Err(e) => error!("Error reading docker events: {:?}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub enum MCServerCommands {
     OP(String),
+    Whitelist(String),
+    Gamemode(String, String),
+    Say(String),
+    Stop,
 }
 
-impl ToString for MCServerCommands {
-    fn to_string(&self) -> String {
+impl MCServerCommands {
+    /// Renders the command the way `rcon-cli` expects it as an argv, i.e.
+    /// without the leading `/` a player would type in-game.
+    fn to_rcon_command(&self) -> String {
         match self {
-            MCServerCommands::OP(name) => { format!("/op {}\n", name) },
+            MCServerCommands::OP(name) => { format!("op {}", name) },
+            MCServerCommands::Whitelist(name) => { format!("whitelist add {}", name) },
+            MCServerCommands::Gamemode(mode, name) => { format!("gamemode {} {}", mode, name) },
+            MCServerCommands::Say(message) => { format!("say {}", message) },
+            MCServerCommands::Stop => { "stop".to_string() },
         }
     }
 }
 
+impl ToString for MCServerCommands {
+    fn to_string(&self) -> String {
+        self.to_rcon_command()
+    }
+}
+
 pub struct MCServerLogOptions {
     limit: String,
 }
@@ -235,16 +476,32 @@ impl Default for MCServerLogOptions {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct MCRestartPolicy {
+    pub name: String,
+    pub maximum_retry_count: u64,
+}
+
 pub struct MCServerOptions {
     volume: PathBuf,
     port: u32,
     name: String,
+    memory: Option<u64>,
+    memory_swap: Option<i64>,
+    cpu_shares: Option<u32>,
+    restart_policy: Option<MCRestartPolicy>,
+    image_tag: Option<String>,
 }
 
 pub struct MCServerOptionsBuilder {
     name: String,
     port: u32,
-    volume: PathBuf
+    volume: PathBuf,
+    memory: Option<u64>,
+    memory_swap: Option<i64>,
+    cpu_shares: Option<u32>,
+    restart_policy: Option<MCRestartPolicy>,
+    image_tag: Option<String>,
 }
 
 impl MCServerOptionsBuilder {
@@ -254,6 +511,11 @@ impl MCServerOptionsBuilder {
             name,
             volume,
             port: DEFAULT_MC_PORT,
+            memory: None,
+            memory_swap: None,
+            cpu_shares: None,
+            restart_policy: None,
+            image_tag: None,
         }
     }
 
@@ -262,11 +524,41 @@ impl MCServerOptionsBuilder {
         self
     }
 
+    pub fn image_tag(mut self, image_tag: String) -> Self {
+        self.image_tag = Some(image_tag);
+        self
+    }
+
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn memory_swap(mut self, memory_swap: i64) -> Self {
+        self.memory_swap = Some(memory_swap);
+        self
+    }
+
+    pub fn cpu_shares(mut self, cpu_shares: u32) -> Self {
+        self.cpu_shares = Some(cpu_shares);
+        self
+    }
+
+    pub fn restart_policy(mut self, name: String, maximum_retry_count: u64) -> Self {
+        self.restart_policy = Some(MCRestartPolicy { name, maximum_retry_count });
+        self
+    }
+
     pub fn build(self) -> MCServerOptions {
         MCServerOptions {
             volume: self.volume,
             port: self.port,
             name: self.name,
+            memory: self.memory,
+            memory_swap: self.memory_swap,
+            cpu_shares: self.cpu_shares,
+            restart_policy: self.restart_policy,
+            image_tag: self.image_tag,
         }
     }
 }